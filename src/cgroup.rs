@@ -0,0 +1,95 @@
+//! Best-effort detection of cgroup CPU quotas so we don't over-provision
+//! worker threads inside a container that's been given less CPU than the
+//! host exposes.
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::fs;
+
+    pub(crate) fn cpu_quota() -> Option<usize> {
+        let v2 = fs::read_to_string("/sys/fs/cgroup/cpu.max")
+            .ok()
+            .and_then(|contents| parse_cgroup_v2_quota(&contents));
+
+        v2.or_else(|| {
+            let quota = fs::read_to_string("/sys/fs/cgroup/cpu/cpu.cfs_quota_us").ok()?;
+            let period = fs::read_to_string("/sys/fs/cgroup/cpu/cpu.cfs_period_us").ok()?;
+            parse_cgroup_v1_quota(&quota, &period)
+        })
+    }
+
+    /// Parses the two space-separated `quota period` fields of cgroup v2's
+    /// `cpu.max`. `None` if `quota` is `"max"` (unlimited) or the contents
+    /// are malformed.
+    fn parse_cgroup_v2_quota(contents: &str) -> Option<usize> {
+        let mut fields = contents.split_whitespace();
+        let quota = fields.next()?;
+        let period: u64 = fields.next()?.parse().ok()?;
+        if quota == "max" {
+            return None;
+        }
+        let quota: u64 = quota.parse().ok()?;
+        Some(quota.div_ceil(period).max(1) as usize)
+    }
+
+    /// Parses cgroup v1's `cpu.cfs_quota_us` and `cpu.cfs_period_us`.
+    /// `None` if `quota` is `-1` (unlimited) or either value is malformed.
+    fn parse_cgroup_v1_quota(quota: &str, period: &str) -> Option<usize> {
+        let quota: i64 = quota.trim().parse().ok()?;
+        if quota < 0 {
+            return None;
+        }
+        let period: u64 = period.trim().parse().ok()?;
+        Some((quota as u64).div_ceil(period).max(1) as usize)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn v2_quota_unlimited() {
+            assert_eq!(parse_cgroup_v2_quota("max 100000\n"), None);
+        }
+
+        #[test]
+        fn v2_quota_exact_division() {
+            assert_eq!(parse_cgroup_v2_quota("200000 100000\n"), Some(2));
+        }
+
+        #[test]
+        fn v2_quota_rounds_up() {
+            assert_eq!(parse_cgroup_v2_quota("250000 100000\n"), Some(3));
+        }
+
+        #[test]
+        fn v2_quota_malformed() {
+            assert_eq!(parse_cgroup_v2_quota("not a number\n"), None);
+        }
+
+        #[test]
+        fn v1_quota_unlimited() {
+            assert_eq!(parse_cgroup_v1_quota("-1\n", "100000\n"), None);
+        }
+
+        #[test]
+        fn v1_quota_rounds_up() {
+            assert_eq!(parse_cgroup_v1_quota("150000\n", "100000\n"), Some(2));
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    pub(crate) fn cpu_quota() -> Option<usize> {
+        None
+    }
+}
+
+/// Number of CPUs the current cgroup is allowed to use, derived from its
+/// CFS quota. `None` means no quota applies (or we couldn't determine one),
+/// in which case `num_cpus()` falls back to the CPUs allowed by the
+/// process's affinity mask.
+pub(crate) fn cpu_quota() -> Option<usize> {
+    sys::cpu_quota()
+}
@@ -1,14 +1,46 @@
 pub mod libnuma;
 
-use std::{cmp::max, io, mem::MaybeUninit};
+mod cgroup;
+
+use std::{cmp::max, io, mem::MaybeUninit, pin::Pin, sync::Arc, time::Duration};
 
 use std::future::Future;
 
+use tokio_util::sync::CancellationToken;
+
+type ThreadHook = Arc<dyn Fn() + Send + Sync>;
+type ShutdownSignal = Pin<Box<dyn Future<Output = ()> + Send>>;
+type WorkerHandles<T> = Vec<(tokio::runtime::Runtime, tokio::task::JoinHandle<Option<T>>)>;
+
+/// Outcome of a single worker under [`WorkerGroup::run_with_shutdown`].
+pub enum WorkerOutcome<T> {
+    /// The worker's entry future completed before the shutdown deadline.
+    Finished(T),
+    /// The worker was cooperatively cancelled via `shutdown_signal` and
+    /// returned before the drain deadline passed.
+    Cancelled,
+    /// The worker didn't drain within `shutdown_timeout`: it was still
+    /// running, or still finishing up after cancellation, when the
+    /// deadline passed.
+    TimedOut,
+    /// The worker's entry task panicked or was aborted.
+    Failed(tokio::task::JoinError),
+}
+
 pub struct WorkerGroup<Init, Entry> {
     #[cfg(feature = "numa-awareness")]
     numa: bool,
     #[cfg(feature = "numa-awareness")]
     workers_per_numa: usize,
+    #[cfg(feature = "numa-awareness")]
+    membind: bool,
+    thread_name_prefix: Option<String>,
+    thread_stack_size: Option<usize>,
+    max_blocking_threads: Option<usize>,
+    on_thread_start: Option<ThreadHook>,
+    on_thread_stop: Option<ThreadHook>,
+    shutdown_timeout: Option<Duration>,
+    shutdown_signal: Option<ShutdownSignal>,
     init: MaybeUninit<Init>,
     entry: Entry,
     #[cfg(not(feature = "numa-awareness"))]
@@ -22,6 +54,15 @@ impl<Init, Entry> WorkerGroup<Init, Entry> {
             numa: false,
             #[cfg(feature = "numa-awareness")]
             workers_per_numa: 1,
+            #[cfg(feature = "numa-awareness")]
+            membind: true,
+            thread_name_prefix: None,
+            thread_stack_size: None,
+            max_blocking_threads: None,
+            on_thread_start: None,
+            on_thread_stop: None,
+            shutdown_timeout: None,
+            shutdown_signal: None,
             init: MaybeUninit::new(|| {}),
             entry: || {},
             #[cfg(not(feature = "numa-awareness"))]
@@ -33,19 +74,92 @@ impl<Init, Entry> WorkerGroup<Init, Entry> {
     pub fn numa(self, enable: bool) -> Self {
         Self {
             numa: enable,
-            workers_per_numa: self.workers_per_numa,
-            init: self.init,
-            entry: self.entry,
+            ..self
         }
     }
 
     #[cfg(feature = "numa-awareness")]
-    pub fn workers_per_numa(self, n: bool) -> Self {
+    pub fn workers_per_numa(self, n: usize) -> Self {
         Self {
-            numa: self.numa,
             workers_per_numa: n,
-            init: self.init,
-            entry: self.entry,
+            ..self
+        }
+    }
+
+    /// Whether worker threads also bind their memory allocations to their
+    /// assigned NUMA node (via [`libnuma::set_membind`]), on top of thread
+    /// affinity. Enabled by default when `numa` is on; pass `false` to pin
+    /// threads without constraining where their memory comes from.
+    #[cfg(feature = "numa-awareness")]
+    pub fn membind(self, enable: bool) -> Self {
+        Self {
+            membind: enable,
+            ..self
+        }
+    }
+
+    /// Prefix used to name worker threads: `<prefix>-<node>-<n>` when the
+    /// `numa-awareness` feature is enabled, or `<prefix>-<n>` otherwise.
+    pub fn thread_name_prefix(self, prefix: impl Into<String>) -> Self {
+        Self {
+            thread_name_prefix: Some(prefix.into()),
+            ..self
+        }
+    }
+
+    pub fn thread_stack_size(self, size: usize) -> Self {
+        Self {
+            thread_stack_size: Some(size),
+            ..self
+        }
+    }
+
+    pub fn max_blocking_threads(self, n: usize) -> Self {
+        Self {
+            max_blocking_threads: Some(n),
+            ..self
+        }
+    }
+
+    /// Runs after a worker thread starts, once the internal NUMA affinity
+    /// and membind hooks (if any) have already run.
+    pub fn on_thread_start<F: Fn() + Send + Sync + 'static>(self, f: F) -> Self {
+        Self {
+            on_thread_start: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    pub fn on_thread_stop<F: Fn() + Send + Sync + 'static>(self, f: F) -> Self {
+        Self {
+            on_thread_stop: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    /// Bounds how long [`WorkerGroup::run_with_shutdown`] waits for each
+    /// worker, counted from when its entry task is spawned: the entry
+    /// future (or its cancellation) has to resolve within this time, and
+    /// whatever is left of it bounds the worker runtime's shutdown. An
+    /// entry future that is still running when it expires is not waited
+    /// for further.
+    pub fn shutdown_timeout(self, dur: Duration) -> Self {
+        Self {
+            shutdown_timeout: Some(dur),
+            ..self
+        }
+    }
+
+    /// A future that, once it resolves, cooperatively cancels every
+    /// worker's entry task so the timeout-bounded drain in
+    /// [`WorkerGroup::run_with_shutdown`] can begin early.
+    pub fn shutdown_signal<F>(self, signal: F) -> Self
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        Self {
+            shutdown_signal: Some(Box::pin(signal)),
+            ..self
         }
     }
 
@@ -60,6 +174,15 @@ impl<Init, Entry> WorkerGroup<Init, Entry> {
             numa: self.numa,
             #[cfg(feature = "numa-awareness")]
             workers_per_numa: self.workers_per_numa,
+            #[cfg(feature = "numa-awareness")]
+            membind: self.membind,
+            thread_name_prefix: self.thread_name_prefix,
+            thread_stack_size: self.thread_stack_size,
+            max_blocking_threads: self.max_blocking_threads,
+            on_thread_start: self.on_thread_start,
+            on_thread_stop: self.on_thread_stop,
+            shutdown_timeout: self.shutdown_timeout,
+            shutdown_signal: self.shutdown_signal,
             init: self.init,
             entry,
             #[cfg(not(feature = "numa-awareness"))]
@@ -73,6 +196,15 @@ impl<Init, Entry> WorkerGroup<Init, Entry> {
             numa: self.numa,
             #[cfg(feature = "numa-awareness")]
             workers_per_numa: self.workers_per_numa,
+            #[cfg(feature = "numa-awareness")]
+            membind: self.membind,
+            thread_name_prefix: self.thread_name_prefix,
+            thread_stack_size: self.thread_stack_size,
+            max_blocking_threads: self.max_blocking_threads,
+            on_thread_start: self.on_thread_start,
+            on_thread_stop: self.on_thread_stop,
+            shutdown_timeout: self.shutdown_timeout,
+            shutdown_signal: self.shutdown_signal,
             init: MaybeUninit::new(init),
             entry: self.entry,
             #[cfg(not(feature = "numa-awareness"))]
@@ -83,9 +215,8 @@ impl<Init, Entry> WorkerGroup<Init, Entry> {
     #[cfg(not(feature = "numa-awareness"))]
     pub fn worker_num(self, num: usize) -> Self {
         Self {
-            init: self.init,
-            entry: self.entry,
             workers: num,
+            ..self
         }
     }
 }
@@ -107,81 +238,286 @@ where
         self.run_workers()
     }
 
+    /// Like [`WorkerGroup::run`], but drains workers instead of dropping
+    /// their runtimes out from under outstanding tasks. Each worker gets
+    /// `shutdown_timeout`, counted from when its entry task is spawned, for
+    /// its entry future to complete or be cancelled by `shutdown_signal`;
+    /// the rest of that budget bounds the shutdown of its runtime. The
+    /// drain does not wait for entry futures to complete before the
+    /// deadline starts running. With no `shutdown_timeout` set, workers are
+    /// waited on indefinitely. Workers that didn't make it within the
+    /// deadline report [`WorkerOutcome::TimedOut`] instead of failing the
+    /// whole call.
+    pub fn run_with_shutdown(mut self) -> io::Result<Vec<WorkerOutcome<G::Output>>> {
+        let init_runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .enable_all()
+            .build()?;
+
+        let _guard = init_runtime.block_on(unsafe { self.init.assume_init_read() });
+
+        let dur = self.shutdown_timeout;
+        let token = CancellationToken::new();
+
+        if let Some(signal) = self.shutdown_signal.take() {
+            let watcher_token = token.clone();
+            init_runtime.spawn(async move {
+                signal.await;
+                watcher_token.cancel();
+            });
+        }
+
+        let handles = self.spawn_workers(&token)?;
+
+        // Drain every worker concurrently on its own thread, so one stuck
+        // worker doesn't serialize the whole call behind `shutdown_timeout`
+        // once per worker.
+        let drains: Vec<_> = handles
+            .into_iter()
+            .map(|(runtime, handle)| {
+                std::thread::spawn(move || match dur {
+                    Some(dur) => {
+                        let start = std::time::Instant::now();
+                        let joined =
+                            runtime.block_on(async { tokio::time::timeout(dur, handle).await });
+
+                        let outcome = match joined {
+                            Ok(Ok(Some(out))) => WorkerOutcome::Finished(out),
+                            Ok(Ok(None)) => WorkerOutcome::Cancelled,
+                            Ok(Err(join_err)) => WorkerOutcome::Failed(join_err),
+                            Err(_elapsed) => WorkerOutcome::TimedOut,
+                        };
+
+                        // The timeout above may already have used up the
+                        // whole budget; don't give the shutdown a fresh
+                        // `dur` on top of it.
+                        runtime.shutdown_timeout(dur.saturating_sub(start.elapsed()));
+                        outcome
+                    }
+                    None => {
+                        let outcome = match runtime.block_on(handle) {
+                            Ok(Some(out)) => WorkerOutcome::Finished(out),
+                            Ok(None) => WorkerOutcome::Cancelled,
+                            Err(join_err) => WorkerOutcome::Failed(join_err),
+                        };
+
+                        // No deadline was requested and the handle has
+                        // already resolved, so there's nothing left to wait
+                        // on; just drop the runtime in the background.
+                        runtime.shutdown_background();
+                        outcome
+                    }
+                })
+            })
+            .collect();
+
+        let mut outcomes = Vec::with_capacity(drains.len());
+        for drain in drains {
+            match drain.join() {
+                Ok(outcome) => outcomes.push(outcome),
+                Err(_) => panic!("worker drain thread panicked"),
+            }
+        }
+
+        drop(init_runtime);
+        Ok(outcomes)
+    }
+
+    /// Builds one runtime per worker the same way `run` does, but returns
+    /// the `(Runtime, JoinHandle)` pairs instead of joining them, and wraps
+    /// each entry future so `token` can cooperatively cancel it. Shared by
+    /// [`Self::run_workers`] (with a token that's never cancelled) and
+    /// [`Self::run_with_shutdown`].
     #[cfg(feature = "numa-awareness")]
-    fn run_workers(self) -> io::Result<Vec<G::Output>> {
+    fn spawn_workers(
+        &self,
+        token: &CancellationToken,
+    ) -> io::Result<WorkerHandles<G::Output>> {
         let nodes = libnuma::numa_max_node()?;
+        let allowed = libnuma::allowed_cpus();
+        let cpuset = libnuma::cgroup_cpuset();
 
         let mut handles = Vec::new();
         for node in 0..nodes {
-            for _ in self.workers_per_numa {
+            let node_cpus: Vec<usize> = libnuma::numa_node_to_cpus(node)
+                .unwrap()
+                .into_iter()
+                .filter(|cpu| allowed.contains(cpu))
+                .filter(|cpu| cpuset.as_ref().is_none_or(|set| set.contains(cpu)))
+                .collect();
+
+            // The cgroup's cpuset may only grant us a slice of this node;
+            // if that slice is empty, the node is unusable to us.
+            if node_cpus.is_empty() {
+                continue;
+            }
+
+            for _ in 0..self.workers_per_numa {
                 let mut builder = tokio::runtime::Builder::new_multi_thread();
+
+                let affinity_cpus = node_cpus.clone();
+                let user_on_start = self.on_thread_start.clone();
+                let numa = self.numa;
+                let membind = self.membind;
                 builder.enable_all().on_thread_start(move || {
-                    if self.numa && libnuma::numa_available() {
-                        let cpus = libnuma::numa_node_to_cpus(node).unwrap();
-                        libnuma::set_thread_affinity(&cpus);
+                    if numa && libnuma::numa_available() {
+                        libnuma::set_thread_affinity(&affinity_cpus);
+                        if membind {
+                            let _ = libnuma::set_membind(node);
+                        }
+                    }
+                    if let Some(f) = &user_on_start {
+                        f();
                     }
                 });
 
-                if self.numa && libnuma::numa_available() {
-                    builder.worker_threads(max(
-                        libnuma::numa_node_to_cpus(node).unwrap().len()
-                            / (nodes * self.workers_per_numa),
-                        1,
-                    ));
+                if let Some(f) = self.on_thread_stop.clone() {
+                    builder.on_thread_stop(move || f());
+                }
+
+                if let Some(prefix) = self.thread_name_prefix.clone() {
+                    let next = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+                    builder.thread_name_fn(move || {
+                        let n = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        format!("{prefix}-{node}-{n}")
+                    });
+                }
+
+                if let Some(size) = self.thread_stack_size {
+                    builder.thread_stack_size(size);
+                }
+
+                if let Some(n) = self.max_blocking_threads {
+                    builder.max_blocking_threads(n);
+                }
+
+                if let Some(n) = worker_threads_override() {
+                    builder.worker_threads(max(n / (nodes * self.workers_per_numa), 1));
+                } else if self.numa && libnuma::numa_available() {
+                    builder.worker_threads(max(node_cpus.len() / (nodes * self.workers_per_numa), 1));
                 } else {
-                    builder.worker_threads(max(num_cpus() / worker_num, 1));
+                    builder.worker_threads(max(num_cpus() / (nodes * self.workers_per_numa), 1));
                 }
 
                 let runtime = builder.build()?;
 
                 let _rt = runtime.enter();
-                let handle = runtime.spawn((self.entry)());
-                handles.push(async move {
-                    let _runtime = runtime;
-                    handle.await
+                let entry_future = (self.entry)();
+                let worker_token = token.clone();
+                let handle = runtime.spawn(async move {
+                    tokio::select! {
+                        biased;
+                        _ = worker_token.cancelled() => None,
+                        out = entry_future => Some(out),
+                    }
                 });
+                handles.push((runtime, handle));
             }
         }
 
+        Ok(handles)
+    }
+
+    #[cfg(feature = "numa-awareness")]
+    fn run_workers(self) -> io::Result<Vec<G::Output>> {
+        let token = CancellationToken::new();
+        let handles = self.spawn_workers(&token)?;
+
+        let awaits = handles.into_iter().map(|(runtime, handle)| async move {
+            let _runtime = runtime;
+            handle.await
+        });
+
         let mut results = Vec::new();
-        for result in futures::executor::block_on(futures::future::join_all(handles)) {
-            results.push(result?);
+        for result in futures::executor::block_on(futures::future::join_all(awaits)) {
+            results.push(result?.expect("entry task is never cancelled here"));
         }
 
         Ok(results)
     }
 
+    /// See the NUMA-aware [`Self::spawn_workers`] above; same shape, no
+    /// node pinning.
     #[cfg(not(feature = "numa-awareness"))]
-    fn run_workers(self) -> io::Result<Vec<G::Output>> {
-        use futures_util::future;
-
+    fn spawn_workers(
+        &self,
+        token: &CancellationToken,
+    ) -> io::Result<WorkerHandles<G::Output>> {
         let mut handles = Vec::new();
         for _ in 0..self.workers {
-            let runtime = tokio::runtime::Builder::new_multi_thread()
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            builder
                 .enable_all()
-                .worker_threads(max(num_cpus() / self.workers, 1))
-                .build()?;
+                .worker_threads(max(num_cpus() / self.workers, 1));
+
+            if let Some(f) = self.on_thread_start.clone() {
+                builder.on_thread_start(move || f());
+            }
+
+            if let Some(f) = self.on_thread_stop.clone() {
+                builder.on_thread_stop(move || f());
+            }
+
+            if let Some(prefix) = self.thread_name_prefix.clone() {
+                let next = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+                builder.thread_name_fn(move || {
+                    let n = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    format!("{prefix}-{n}")
+                });
+            }
+
+            if let Some(size) = self.thread_stack_size {
+                builder.thread_stack_size(size);
+            }
+
+            if let Some(n) = self.max_blocking_threads {
+                builder.max_blocking_threads(n);
+            }
+
+            let runtime = builder.build()?;
 
             let _rt = runtime.enter();
-            let handle = runtime.spawn((self.entry)());
-            handles.push(async move {
-                let _runtime = runtime;
-                handle.await
+            let entry_future = (self.entry)();
+            let worker_token = token.clone();
+            let handle = runtime.spawn(async move {
+                tokio::select! {
+                    biased;
+                    _ = worker_token.cancelled() => None,
+                    out = entry_future => Some(out),
+                }
             });
+            handles.push((runtime, handle));
         }
 
+        Ok(handles)
+    }
+
+    #[cfg(not(feature = "numa-awareness"))]
+    fn run_workers(self) -> io::Result<Vec<G::Output>> {
+        use futures_util::future;
+
+        let token = CancellationToken::new();
+        let handles = self.spawn_workers(&token)?;
+
+        let awaits = handles.into_iter().map(|(runtime, handle)| async move {
+            let _runtime = runtime;
+            handle.await
+        });
+
         let mut results = Vec::new();
-        for result in spin_on::spin_on(future::join_all(handles)) {
-            results.push(result?);
+        for result in spin_on::spin_on(future::join_all(awaits)) {
+            results.push(result?.expect("entry task is never cancelled here"));
         }
 
         Ok(results)
     }
 }
 
-pub(crate) fn num_cpus() -> usize {
-    const ENV_WORKER_THREADS: &str = "TOKIO_WORKER_THREADS";
+const ENV_WORKER_THREADS: &str = "TOKIO_WORKER_THREADS";
 
+/// `TOKIO_WORKER_THREADS`, if set, overriding every other worker-count
+/// heuristic (cgroup quota, NUMA node sizing, ...).
+pub(crate) fn worker_threads_override() -> Option<usize> {
     match std::env::var(ENV_WORKER_THREADS) {
         Ok(s) => {
             let n = s.parse().unwrap_or_else(|e| {
@@ -191,9 +527,9 @@ pub(crate) fn num_cpus() -> usize {
                 )
             });
             assert!(n > 0, "\"{}\" cannot be set to 0", ENV_WORKER_THREADS);
-            n
+            Some(n)
         }
-        Err(std::env::VarError::NotPresent) => usize::max(1, num_cpus::get()),
+        Err(std::env::VarError::NotPresent) => None,
         Err(std::env::VarError::NotUnicode(e)) => {
             panic!(
                 "\"{}\" must be valid unicode, error: {:?}",
@@ -202,3 +538,63 @@ pub(crate) fn num_cpus() -> usize {
         }
     }
 }
+
+pub(crate) fn num_cpus() -> usize {
+    if let Some(n) = worker_threads_override() {
+        return n;
+    }
+
+    let allowed = usize::max(1, libnuma::allowed_cpus().len());
+    match cgroup::cpu_quota() {
+        Some(quota) => usize::max(1, usize::min(quota, allowed)),
+        None => allowed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_with_shutdown_without_timeout_does_not_panic() {
+        let outcomes = WorkerGroup::<(), ()>::new()
+            .init(async {})
+            .entry(|| async { 42 })
+            .run_with_shutdown()
+            .unwrap();
+
+        assert_eq!(outcomes.len(), 1);
+        assert!(matches!(outcomes[0], WorkerOutcome::Finished(42)));
+    }
+
+    #[test]
+    fn run_with_shutdown_reports_cancellation() {
+        let outcomes = WorkerGroup::<(), ()>::new()
+            .init(async {})
+            .entry(|| async {
+                tokio::time::sleep(Duration::from_secs(3600)).await;
+            })
+            .shutdown_timeout(Duration::from_millis(200))
+            .shutdown_signal(async {})
+            .run_with_shutdown()
+            .unwrap();
+
+        assert_eq!(outcomes.len(), 1);
+        assert!(matches!(outcomes[0], WorkerOutcome::Cancelled));
+    }
+
+    #[test]
+    fn run_with_shutdown_reports_timed_out_worker() {
+        let outcomes = WorkerGroup::<(), ()>::new()
+            .init(async {})
+            .entry(|| async {
+                tokio::time::sleep(Duration::from_secs(3600)).await;
+            })
+            .shutdown_timeout(Duration::from_millis(50))
+            .run_with_shutdown()
+            .unwrap();
+
+        assert_eq!(outcomes.len(), 1);
+        assert!(matches!(outcomes[0], WorkerOutcome::TimedOut));
+    }
+}
@@ -8,12 +8,11 @@ mod sys {
         libc::syscall(libc::SYS_get_mempolicy, &mut (), &mut (), 0, 0, 0) < 0
     }
 
-    pub(crate) fn nume_nodes() -> io::Result<Vec<usize>> {
+    pub(crate) fn numa_max_node() -> io::Result<usize> {
         Ok(fs::read_dir("/sys/devices/system/node")?
             .filter_map(Result::ok)
-            .map(|d| d.file_name().to_string_lossy())
+            .map(|d| d.file_name().to_string_lossy().into_owned())
             .filter(|d| d.starts_with("node"))
-            .map(|d| d[4..].parse::<usize>())
             .count())
     }
 
@@ -33,6 +32,126 @@ mod sys {
                 .collect(),
         )
     }
+
+    pub(crate) fn allowed_cpus() -> Vec<usize> {
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) == 0 {
+                (0..libc::CPU_SETSIZE as usize)
+                    .filter(|&cpu| libc::CPU_ISSET(cpu, &set))
+                    .collect()
+            } else {
+                let n = libc::sysconf(libc::_SC_NPROCESSORS_ONLN).max(1) as usize;
+                (0..n).collect()
+            }
+        }
+    }
+
+    pub(crate) fn cgroup_cpuset() -> Option<Vec<usize>> {
+        let contents = fs::read_to_string("/sys/fs/cgroup/cpuset.cpus")
+            .or_else(|_| fs::read_to_string("/sys/fs/cgroup/cpuset/cpuset.cpus"))
+            .ok()?;
+        let cpus = parse_cpulist(contents.trim());
+        // An empty `cpuset.cpus` means no cpuset has been set on this cgroup.
+        if cpus.is_empty() {
+            None
+        } else {
+            Some(cpus)
+        }
+    }
+
+    fn parse_cpulist(list: &str) -> Vec<usize> {
+        let mut cpus = Vec::new();
+        for part in list.split(',') {
+            let part = part.trim();
+            if part.is_empty() {
+                continue;
+            }
+            match part.split_once('-') {
+                Some((start, end)) => {
+                    if let (Ok(start), Ok(end)) = (start.parse::<usize>(), end.parse::<usize>()) {
+                        cpus.extend(start..=end);
+                    }
+                }
+                None => {
+                    if let Ok(id) = part.parse::<usize>() {
+                        cpus.push(id);
+                    }
+                }
+            }
+        }
+        cpus
+    }
+
+    // Not exposed by the `libc` crate's `MPOL_*` bindings on all targets, so
+    // define the one constant we need ourselves.
+    #[cfg(feature = "numa-awareness")]
+    const MPOL_BIND: libc::c_ulong = 2;
+
+    #[cfg(feature = "numa-awareness")]
+    pub(crate) fn set_membind(node: usize) -> io::Result<()> {
+        // `nodemask` below is a single `c_ulong`, so it can only describe
+        // nodes `0..c_ulong::BITS`; refuse rather than silently wrapping
+        // the shift or lying to the kernel about how many bits follow it.
+        if node >= libc::c_ulong::BITS as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "NUMA node {node} is out of range for a {}-bit nodemask",
+                    libc::c_ulong::BITS
+                ),
+            ));
+        }
+
+        let maxnode = (super::numa_max_node()? as libc::c_ulong + 1)
+            .min(libc::c_ulong::BITS as libc::c_ulong + 1);
+        let nodemask: libc::c_ulong = 1 << node;
+
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_set_mempolicy,
+                MPOL_BIND,
+                &nodemask as *const libc::c_ulong,
+                maxnode,
+            )
+        };
+
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn single_ids() {
+            assert_eq!(parse_cpulist("0,2,5"), vec![0, 2, 5]);
+        }
+
+        #[test]
+        fn range() {
+            assert_eq!(parse_cpulist("2-4"), vec![2, 3, 4]);
+        }
+
+        #[test]
+        fn combined_list() {
+            assert_eq!(parse_cpulist("0,2-3,7"), vec![0, 2, 3, 7]);
+        }
+
+        #[test]
+        fn empty_string() {
+            assert_eq!(parse_cpulist(""), Vec::<usize>::new());
+        }
+
+        #[test]
+        fn ignores_malformed_entries() {
+            assert_eq!(parse_cpulist("0,bogus,3"), vec![0, 3]);
+        }
+    }
 }
 
 #[cfg(not(target_os = "linux"))]
@@ -62,6 +181,19 @@ mod sys {
         let _ = node;
         Err(io::Error::new(io::ErrorKind::Other, NotSupport))
     }
+
+    pub(crate) fn allowed_cpus() -> Vec<usize> {
+        (0..num_cpus::get()).collect()
+    }
+
+    pub(crate) fn set_membind(node: usize) -> io::Result<()> {
+        let _ = node;
+        Ok(())
+    }
+
+    pub(crate) fn cgroup_cpuset() -> Option<Vec<usize>> {
+        None
+    }
 }
 
 pub fn numa_available() -> bool {
@@ -76,6 +208,28 @@ pub fn numa_node_to_cpus(node: usize) -> io::Result<Vec<usize>> {
     sys::numa_node_to_cpus(node)
 }
 
+/// CPUs the current process is allowed to run on, per `sched_getaffinity`.
+/// Falls back to the online CPU count if the affinity mask can't be read.
+pub fn allowed_cpus() -> Vec<usize> {
+    sys::allowed_cpus()
+}
+
+/// Binds the calling thread's future memory allocations to `node` via
+/// `MPOL_BIND`, so a worker pinned to a NUMA node's CPUs also gets
+/// node-local memory instead of whatever the kernel's default policy picks.
+/// No-op on non-Linux targets.
+#[cfg(feature = "numa-awareness")]
+pub fn set_membind(node: usize) -> io::Result<()> {
+    sys::set_membind(node)
+}
+
+/// CPUs granted to the current cgroup's `cpuset.cpus` (v2) or
+/// `cpuset/cpuset.cpus` (v1), expanded from the Linux cpulist format
+/// (`"0,2-3"`-style). `None` if no cpuset restriction could be found.
+pub fn cgroup_cpuset() -> Option<Vec<usize>> {
+    sys::cgroup_cpuset()
+}
+
 pub fn set_thread_affinity(cpus: &[usize]) {
     #[cfg(all(target_os = "linux", feature = "affinity"))]
     affinity::set_thread_affinity(cpus);